@@ -9,6 +9,8 @@ pub mod static_anymap;
 pub extern crate lazy_static;
 #[doc(hidden)]
 pub mod thread_local_static_anymap;
+#[doc(hidden)]
+pub mod tls_cell;
 
 /// Get a static reference to a generic singleton or initialize it if it doesn't exist.
 ///
@@ -69,9 +71,186 @@ macro_rules! get_or_init {
     }};
 }
 
+/// Same as the [get_or_init!] macro, but `init` is handed a pinned pointer to uninitialized
+/// memory to fill in place, instead of constructing a value that then gets moved into its final
+/// storage. This makes it possible to initialize self-referential or address-sensitive types
+/// (an intrusive list node, a `Mutex` paired with a `Condvar` that registers its own address),
+/// the same problem the Linux kernel's pin-init API solves.
+///
+/// `init` must fully initialize the `MaybeUninit<T>` it's handed before returning `Ok`; the
+/// singleton is only published once it does. On `Err`, nothing is inserted, so a later call can
+/// retry.
+///
+/// ### Example
+/// ```rust
+/// use std::marker::PhantomPinned;
+/// use std::mem::MaybeUninit;
+/// use std::pin::Pin;
+/// use std::ptr;
+///
+/// // Holds a pointer back into its own `value` field, so it can never be moved after
+/// // initialization.
+/// struct SelfReferential {
+///     value: u32,
+///     value_ptr: *const u32,
+///     _pin: PhantomPinned,
+/// }
+///
+/// // SAFETY: `value_ptr` only ever points back into the same `SelfReferential`, which is
+/// // immutable and pinned after initialization, so sharing a `&SelfReferential` across threads
+/// // is as safe as sharing the `u32` it points to.
+/// unsafe impl Sync for SelfReferential {}
+///
+/// impl SelfReferential {
+///     fn value(&self) -> u32 {
+///         // SAFETY: `value_ptr` was set to point at `self.value` during initialization, and
+///         // this type can never move afterwards, since it's pinned.
+///         unsafe { *self.value_ptr }
+///     }
+/// }
+///
+/// fn get() -> &'static SelfReferential {
+///     generic_singleton::get_or_pin_init!(|slot: Pin<&mut MaybeUninit<SelfReferential>>| {
+///         // SAFETY: we only write fields in place through raw pointers below; we never move
+///         // the pointee, which is required since it may not be `Unpin`.
+///         let ptr = unsafe { slot.get_unchecked_mut() }.as_mut_ptr();
+///         // SAFETY: `ptr` is valid for writes and properly aligned, since it comes from a
+///         // live allocation handed to us by `get_or_pin_init!`.
+///         unsafe {
+///             ptr::addr_of_mut!((*ptr).value).write(42);
+///             ptr::addr_of_mut!((*ptr).value_ptr).write(ptr::addr_of!((*ptr).value));
+///             ptr::addr_of_mut!((*ptr)._pin).write(PhantomPinned);
+///         }
+///         Ok::<(), std::convert::Infallible>(())
+///     })
+///     .unwrap_or_else(|never: std::convert::Infallible| match never {})
+/// }
+///
+/// fn main() {
+///     assert_eq!(get().value(), 42);
+/// }
+/// ```
+#[macro_export]
+macro_rules! get_or_pin_init {
+    ($init:expr) => {{
+        use $crate::lazy_static::lazy_static;
+        use $crate::static_anymap::StaticAnyMap;
+
+        lazy_static! {
+            static ref STATIC_ANY_MAP: StaticAnyMap = StaticAnyMap::default();
+        }
+        STATIC_ANY_MAP.get_or_pin_init($init)
+    }};
+}
+
+/// Same as the [get_or_init!] macro, but for an initializer that can fail (opening a config
+/// file, connecting to a server, parsing an environment variable). If `init` returns `Err`,
+/// nothing is inserted into the singleton map, so a later call can retry.
+///
+/// ### Example
+/// ```rust
+/// use std::str::FromStr;
+///
+/// fn get_parsed_env_var<T>(key: &str) -> Result<&'static T, T::Err>
+/// where
+///     T: FromStr + Sync + 'static,
+/// {
+///     generic_singleton::get_or_try_init!(|| {
+///         std::env::var(key).unwrap_or_default().parse::<T>()
+///     })
+/// }
+///
+/// fn main() {
+///     // `GENERIC_SINGLETON_PORT` isn't set yet, so parsing fails and nothing is cached.
+///     assert!(get_parsed_env_var::<u16>("GENERIC_SINGLETON_PORT").is_err());
+///
+///     // Since the failed call above didn't insert anything, this retries the initializer
+///     // instead of returning a cached error.
+///     std::env::set_var("GENERIC_SINGLETON_PORT", "8080");
+///     assert_eq!(get_parsed_env_var::<u16>("GENERIC_SINGLETON_PORT"), Ok(&8080));
+/// }
+/// ```
+#[macro_export]
+macro_rules! get_or_try_init {
+    ($init:expr) => {{
+        use $crate::lazy_static::lazy_static;
+        use $crate::static_anymap::StaticAnyMap;
+
+        lazy_static! {
+            static ref STATIC_ANY_MAP: StaticAnyMap = StaticAnyMap::default();
+        }
+        STATIC_ANY_MAP.get_or_try_init($init)
+    }};
+}
+
+/// When `init` is a `const` expression, this skips the [get_or_init!] macro's usual
+/// `lazy_static` + `RwLock` + `AnyMap` lookup entirely: the macro expands to `&const { init }`,
+/// so the value is computed at compile time and the borrow is promoted to a `&'static` reference,
+/// making every access branch-free.
+///
+/// Promotion only applies to values that have no interior mutability and don't need dropping, so
+/// this fails to compile for e.g. a `Cell`, a `Mutex`, a `String` or a `Vec`. It also fails to
+/// compile if `init` captures anything or isn't const-evaluable (with whatever diagnostic the
+/// compiler gives for the non-const operation) rather than silently falling back to the slow
+/// path.
+///
+/// ### Example
+/// ```rust
+/// fn pi() -> &'static f64 {
+///     generic_singleton::get_or_init_const!(|| 3.14159)
+/// }
+///
+/// fn main() {
+///     let a = pi();
+///     let b = pi();
+///     assert_eq!(*a, 3.14159);
+///     assert!(std::ptr::eq(a, b));
+/// }
+/// ```
+#[macro_export]
+macro_rules! get_or_init_const {
+    (|| $init:expr) => {
+        &const { $init }
+    };
+}
+
+/// Same as [get_or_init_const!], but for thread-local storage. An explicit type has to be given
+/// for the singleton, since (unlike [get_or_init_const!]) the macro can't infer it purely from
+/// `init`'s return type: a `thread_local!` static still needs to be written out as a concrete
+/// item. The `with` callback works exactly like in [get_or_init_thread_local!].
+///
+/// ### Example
+/// ```rust
+/// use std::cell::Cell;
+///
+/// fn next_id() -> u32 {
+///     generic_singleton::get_or_init_thread_local_const!(Cell<u32>, || Cell::new(0), |cell| {
+///         let id = cell.get();
+///         cell.set(id + 1);
+///         id
+///     })
+/// }
+///
+/// fn main() {
+///     assert_eq!(next_id(), 0);
+///     assert_eq!(next_id(), 1);
+///     assert_eq!(next_id(), 2);
+/// }
+/// ```
+#[macro_export]
+macro_rules! get_or_init_thread_local_const {
+    ($ty:ty, || $init:expr, $with:expr) => {{
+        ::std::thread_local! {
+            static VALUE: $ty = const { $init };
+        }
+        VALUE.with($with)
+    }};
+}
+
 /// Same as the [get_or_init!] macro but using thread local storage. Similar to the [thread_local!]
-/// macro API, we use a closure that yields a mutable reference to your struct. The closure ensures
-/// the reference cannot escape to a different thread.
+/// macro API, we use a closure that yields a reference to your struct. The closure ensures the
+/// reference cannot escape to a different thread, and, mirroring [`std::thread::LocalKey::with`],
+/// whatever the closure returns is returned by the macro.
 ///
 /// ### Example
 /// ```rust
@@ -80,14 +259,12 @@ macro_rules! get_or_init {
 /// use std::ops::AddAssign;
 ///
 /// fn generic_call_counter<T: Zero + One + Copy + AddAssign + Send + 'static>() -> T {
-///     let mut output = T::zero();
 ///     generic_singleton::get_or_init_thread_local!(|| Cell::new(T::zero()), |count_cell| {
 ///         let mut count = count_cell.get();
 ///         count += T::one();
 ///         count_cell.set(count);
-///         output = count;
-///     });
-///     output
+///         count
+///     })
 /// }
 ///
 /// fn main() {
@@ -116,6 +293,148 @@ macro_rules! get_or_init_thread_local {
     }};
 }
 
+/// Same as the [get_or_init_thread_local!] macro, but each value's destructor is registered when
+/// it's initialized, and the registered destructors of this call site run in reverse order of
+/// initialization when the thread exits. (Values of [get_or_init_thread_local!] are dropped at
+/// thread exit too, together with their map, in no specified order.)
+///
+/// ### Example
+/// ```rust
+/// use std::cell::Cell;
+///
+/// struct LoudDrop(&'static str);
+///
+/// impl Drop for LoudDrop {
+///     fn drop(&mut self) {
+///         println!("dropping {}", self.0);
+///     }
+/// }
+///
+/// fn main() {
+///     std::thread::spawn(|| {
+///         generic_singleton::get_or_init_thread_local_drop!(
+///             || Cell::new(LoudDrop("thread-local singleton")),
+///             |_cell| {}
+///         );
+///         // `LoudDrop` is dropped here, when the thread exits.
+///     })
+///     .join()
+///     .unwrap();
+/// }
+/// ```
+#[macro_export]
+macro_rules! get_or_init_thread_local_drop {
+    ($init:expr, $with:expr) => {{
+        use $crate::thread_local_static_anymap::ThreadLocalStaticAnymap;
+        thread_local!(static STATIC_ANY_MAP: ThreadLocalStaticAnymap = ThreadLocalStaticAnymap::default());
+        STATIC_ANY_MAP.with(|map| map.get_or_init_with_drop($init, $with))
+    }};
+}
+
+/// Returns a copy of the thread-local, [`Default`]-initialized, `Copy` generic singleton for
+/// `T`. Shorthand for [get_or_init_thread_local!] with a [`Cell<T>`](std::cell::Cell), mirroring
+/// [`Cell::get`](std::cell::Cell::get).
+///
+/// `T` is usually inferred from context, but can be given explicitly as `tls_get!(T)`.
+///
+/// ### Example
+/// ```rust
+/// use generic_singleton::tls_get;
+///
+/// assert_eq!(tls_get!(u32), 0);
+/// ```
+#[macro_export]
+macro_rules! tls_get {
+    () => {
+        $crate::tls_cell::get()
+    };
+    ($t:ty) => {
+        $crate::tls_cell::get::<$t>()
+    };
+}
+
+/// Sets the thread-local, `Copy` generic singleton for `T` to `value`. Shorthand for
+/// [get_or_init_thread_local!] with a [`Cell<T>`](std::cell::Cell), mirroring
+/// [`Cell::set`](std::cell::Cell::set).
+///
+/// ### Example
+/// ```rust
+/// use generic_singleton::{tls_get, tls_set};
+///
+/// tls_set!(42u32);
+/// assert_eq!(tls_get!(u32), 42);
+/// ```
+#[macro_export]
+macro_rules! tls_set {
+    ($value:expr) => {
+        $crate::tls_cell::set($value)
+    };
+}
+
+/// Resets the thread-local, `Copy` generic singleton for `T` to `T::default()` and returns its
+/// previous value. Shorthand for [get_or_init_thread_local!] with a [`Cell<T>`](std::cell::Cell),
+/// mirroring [`Cell::take`](std::cell::Cell::take).
+///
+/// `T` is usually inferred from context, but can be given explicitly as `tls_take!(T)`.
+///
+/// ### Example
+/// ```rust
+/// use generic_singleton::{tls_set, tls_take};
+///
+/// tls_set!(42u32);
+/// assert_eq!(tls_take!(u32), 42);
+/// assert_eq!(tls_take!(u32), 0);
+/// ```
+#[macro_export]
+macro_rules! tls_take {
+    () => {
+        $crate::tls_cell::take()
+    };
+    ($t:ty) => {
+        $crate::tls_cell::take::<$t>()
+    };
+}
+
+/// Sets the thread-local, `Copy` generic singleton for `T` to `value` and returns its previous
+/// value. Shorthand for [get_or_init_thread_local!] with a [`Cell<T>`](std::cell::Cell),
+/// mirroring [`Cell::replace`](std::cell::Cell::replace).
+///
+/// ### Example
+/// ```rust
+/// use generic_singleton::{tls_get, tls_replace};
+///
+/// assert_eq!(tls_replace!(42u32), 0);
+/// assert_eq!(tls_get!(u32), 42);
+/// ```
+#[macro_export]
+macro_rules! tls_replace {
+    ($value:expr) => {
+        $crate::tls_cell::replace($value)
+    };
+}
+
+/// Runs `with` on a mutable reference to the thread-local, [`Default`]-initialized generic
+/// singleton for `T`, returning whatever `with` returns. Shorthand for
+/// [get_or_init_thread_local!] with a [`RefCell<T>`](std::cell::RefCell), mirroring
+/// [`RefCell::borrow_mut`](std::cell::RefCell::borrow_mut).
+///
+/// ### Example
+/// ```rust
+/// use generic_singleton::tls_with_borrow_mut;
+///
+/// let len = tls_with_borrow_mut!(|v: &mut Vec<u32>| {
+///     v.push(1);
+///     v.len()
+/// });
+/// assert_eq!(len, 1);
+/// ```
+#[macro_export]
+macro_rules! tls_with_borrow_mut {
+    ($with:expr) => {
+        $crate::tls_cell::with_borrow_mut($with)
+    };
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -126,12 +445,10 @@ mod tests {
 
     fn local_testing_function() -> i32 {
         use std::cell::Cell;
-        let mut r = 0;
         get_or_init_thread_local!(|| Cell::new(0), |a| {
             a.set(a.get() + 1);
-            r = a.get();
-        });
-        r
+            a.get()
+        })
     }
 
     #[test]
@@ -141,6 +458,52 @@ mod tests {
         assert_eq!(local_testing_function(), 3);
     }
 
+    #[test]
+    fn thread_local_drop_runs_destructor_on_thread_exit() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+        struct CountDrop;
+        impl Drop for CountDrop {
+            fn drop(&mut self) {
+                DROPPED.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        std::thread::spawn(|| {
+            get_or_init_thread_local_drop!(|| CountDrop, |_| ());
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(DROPPED.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn tls_get_set_take_replace_work() {
+        assert_eq!(tls_get!(u32), 0);
+
+        tls_set!(1u32);
+        assert_eq!(tls_get!(u32), 1);
+
+        assert_eq!(tls_replace!(2u32), 1);
+        assert_eq!(tls_get!(u32), 2);
+
+        assert_eq!(tls_take!(u32), 2);
+        assert_eq!(tls_get!(u32), 0);
+    }
+
+    #[test]
+    fn tls_with_borrow_mut_works() {
+        let len = tls_with_borrow_mut!(|v: &mut Vec<u32>| {
+            v.push(1);
+            v.push(2);
+            v.len()
+        });
+        assert_eq!(len, 2);
+    }
+
     #[test]
     fn works() {
         let a = testing_function();
@@ -148,6 +511,84 @@ mod tests {
         assert!(std::ptr::eq(a, b));
     }
 
+    #[test]
+    fn pin_init_works() {
+        fn pin_initialized() -> &'static i32 {
+            use std::mem::MaybeUninit;
+            use std::pin::Pin;
+
+            get_or_pin_init!(|slot: Pin<&mut MaybeUninit<i32>>| {
+                Pin::get_mut(slot).write(42);
+                Ok::<(), std::convert::Infallible>(())
+            })
+            .unwrap_or_else(|never: std::convert::Infallible| match never {})
+        }
+
+        let a = pin_initialized();
+        let b = pin_initialized();
+        assert_eq!(*a, 42);
+        assert!(std::ptr::eq(a, b));
+    }
+
+    #[test]
+    fn pin_init_error_does_not_insert() {
+        fn fallibly_pin_init(fail: bool) -> Result<&'static i32, &'static str> {
+            use std::mem::MaybeUninit;
+            use std::pin::Pin;
+
+            get_or_pin_init!(|slot: Pin<&mut MaybeUninit<i32>>| {
+                if fail {
+                    return Err("nope");
+                }
+                Pin::get_mut(slot).write(7);
+                Ok(())
+            })
+        }
+
+        assert_eq!(fallibly_pin_init(true), Err("nope"));
+        assert_eq!(fallibly_pin_init(false), Ok(&7));
+    }
+
+    #[test]
+    fn try_init_error_does_not_insert() {
+        fn fallibly_init(fail: bool) -> Result<&'static i32, &'static str> {
+            get_or_try_init!(|| if fail { Err("nope") } else { Ok(7) })
+        }
+
+        assert_eq!(fallibly_init(true), Err("nope"));
+        assert_eq!(fallibly_init(false), Ok(&7));
+        assert_eq!(fallibly_init(true), Ok(&7));
+    }
+
+    #[test]
+    fn const_init_works() {
+        fn const_initialized() -> &'static i32 {
+            get_or_init_const!(|| 1 + 1)
+        }
+
+        let a = const_initialized();
+        let b = const_initialized();
+        assert_eq!(*a, 2);
+        assert!(std::ptr::eq(a, b));
+    }
+
+    #[test]
+    fn thread_local_const_init_works() {
+        use std::cell::Cell;
+
+        fn counter() -> u32 {
+            get_or_init_thread_local_const!(Cell<u32>, || Cell::new(0), |cell| {
+                let count = cell.get() + 1;
+                cell.set(count);
+                count
+            })
+        }
+
+        assert_eq!(counter(), 1);
+        assert_eq!(counter(), 2);
+        assert_eq!(counter(), 3);
+    }
+
     #[test]
     fn recursive_call_to_get_or_init_does_not_panic() {
         get_or_init!(|| get_or_init!(|| 0));
@@ -1,3 +1,4 @@
+use std::mem::MaybeUninit;
 use std::pin::Pin;
 
 use anymap::AnyMap;
@@ -6,7 +7,8 @@ use parking_lot::RwLock;
 /// The point of this struct is to wrap the AnyMap in a concurrent, static version that will only
 /// insert items that have 'static lifetimes. This is acieved by wrapping all items in
 /// `Pin<Box<T>>` and never removing items. This module only exposes the StaticAnyMap struct and
-/// it's get_or_init method. Using these should be perfectly safe.
+/// its get_or_init, get_or_try_init and get_or_pin_init methods. Using these should be perfectly
+/// safe.
 #[derive(Default)]
 pub struct StaticAnyMap {
     inner: RwLock<AnyMap>,
@@ -61,6 +63,85 @@ impl StaticAnyMap {
             self.init_and_return(init)
         }
     }
+
+    /// Like [`get_or_init`](Self::get_or_init), but for an initializer that can fail. If `init`
+    /// returns `Err`, nothing is inserted into the map, so a later call can retry.
+    pub fn get_or_try_init<T: Sync + 'static, E>(
+        &'static self,
+        init: impl FnOnce() -> Result<T, E>,
+    ) -> Result<&'static T, E> {
+        if let Some(val) = self.get() {
+            return Ok(val);
+        }
+
+        let mut writeable_map = self.inner.write();
+
+        // Another thread might have initialized `T` while we were waiting for the write lock.
+        if let Some(val) = writeable_map.get::<Pin<Box<T>>>() {
+            // SAFETY:
+            // Since we only insert values into the map and we wrap the values in Pin<Box<T>> and
+            // the map itself has a static lifetime, we can be sure that the data being pointed to
+            // has static lifetime.
+            return Ok(unsafe { convert_to_static_ref(val) });
+        }
+
+        let value = init()?;
+        let val = writeable_map.entry().or_insert_with(|| Box::pin(value));
+
+        // SAFETY:
+        // Since we only insert values into the map and we wrap the values in Pin<Box<T>> and the
+        // map itself has a static lifetime, we can be sure that the data being pointed to has
+        // static lifetime.
+        Ok(unsafe { convert_to_static_ref(val) })
+    }
+
+    /// Like [`get_or_init`](Self::get_or_init), but `init` is handed a pinned pointer to
+    /// uninitialized memory to fill in place, instead of constructing a `T` by value that then
+    /// gets moved into the box. This makes it possible to initialize self-referential or
+    /// address-sensitive types, which can't tolerate being moved after construction.
+    ///
+    /// The entry is only published into the map once `init` returns `Ok`; on `Err` the
+    /// allocation is dropped and nothing is inserted, so a later call can retry.
+    pub fn get_or_pin_init<T: Sync + 'static, E>(
+        &'static self,
+        init: impl FnOnce(Pin<&mut MaybeUninit<T>>) -> Result<(), E>,
+    ) -> Result<&'static T, E> {
+        if let Some(val) = self.get() {
+            return Ok(val);
+        }
+
+        let mut writeable_map = self.inner.write();
+
+        // Another thread might have initialized `T` while we were waiting for the write lock.
+        if let Some(val) = writeable_map.get::<Pin<Box<T>>>() {
+            // SAFETY:
+            // Since we only insert values into the map and we wrap the values in Pin<Box<T>> and
+            // the map itself has a static lifetime, we can be sure that the data being pointed to
+            // has static lifetime.
+            return Ok(unsafe { convert_to_static_ref(val) });
+        }
+
+        let mut boxed: Box<MaybeUninit<T>> = Box::new(MaybeUninit::uninit());
+        // SAFETY:
+        // `boxed` is a fresh heap allocation that nothing else can reference yet, so there's
+        // nothing to invalidate by pinning it. We never move the pointee out of `boxed` before
+        // `assume_init` below, and `assume_init` itself doesn't move it either (it just changes
+        // the type we view the same allocation as).
+        let pinned = unsafe { Pin::new_unchecked(&mut *boxed) };
+        init(pinned)?;
+
+        // SAFETY:
+        // `init` returned `Ok`, and its contract requires it to have fully initialized the
+        // `MaybeUninit<T>` it was handed in that case.
+        let boxed: Box<T> = unsafe { boxed.assume_init() };
+        let val = writeable_map.entry().or_insert_with(|| Pin::from(boxed));
+
+        // SAFETY:
+        // Since we only insert values into the map and we wrap the values in Pin<Box<T>> and the
+        // map itself has a static lifetime, we can be sure that the data being pointed to has
+        // static lifetime.
+        Ok(unsafe { convert_to_static_ref(val) })
+    }
 }
 
 // # Safety
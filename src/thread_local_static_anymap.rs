@@ -5,9 +5,18 @@ use std::cell::UnsafeCell;
 /// items that have 'static lifetimes. This is acieved by wrapping all items in `Box<T>` and never
 /// removing items. This module only exposes the ThreadLocalStaticAnymap struct and it's
 /// get_or_init_with method. Using these should be perfectly safe.
+///
+/// All values live until this map is dropped, which happens when the thread it lives in exits.
+/// Values inserted via [`get_or_init_with_drop`](ThreadLocalStaticAnymap::get_or_init_with_drop)
+/// have their destructor registered and run first, in reverse insertion order. Values inserted
+/// via [`get_or_init_with`](ThreadLocalStaticAnymap::get_or_init_with) are dropped afterwards,
+/// together with the inner map, in no specified order.
 #[derive(Default)]
 pub struct ThreadLocalStaticAnymap {
     inner: UnsafeCell<AnyMap>,
+    // Destructors for entries inserted via `get_or_init_with_drop`, run in reverse insertion
+    // order from this struct's `Drop` impl.
+    destructors: UnsafeCell<Vec<Box<dyn FnOnce()>>>,
 }
 
 // SAFETY:
@@ -19,7 +28,11 @@ impl ThreadLocalStaticAnymap {
     /// Users need to ensure this is only called from a ThreadLocalStaticAnymap in thread local
     /// storge and that the `with` closure doesn't contain references to the same
     /// ThreadLocalStaticAnymap.
-    pub fn get_or_init_with<T: 'static>(&self, init: impl FnOnce() -> T, with: impl FnOnce(&T)) {
+    pub fn get_or_init_with<T: 'static, R>(
+        &self,
+        init: impl FnOnce() -> T,
+        with: impl FnOnce(&T) -> R,
+    ) -> R {
         let optional_t: &UnsafeCell<Option<T>> = {
             // SAFETY:
             // The pointer returned by `self.inner.get()` is guarantee to be valid, properly aligned
@@ -74,6 +87,76 @@ impl ThreadLocalStaticAnymap {
 
         with(t_ref)
     }
+
+    /// Safety:
+    /// Users need to ensure this is only called from a ThreadLocalStaticAnymap in thread local
+    /// storage and that the `with` closure doesn't contain references to the same
+    /// ThreadLocalStaticAnymap.
+    ///
+    /// Unlike [`get_or_init_with`](Self::get_or_init_with), the first time `T` is initialized a
+    /// destructor for it is registered. It runs (in reverse insertion order, alongside the
+    /// destructors of every other type stored this way) when this `ThreadLocalStaticAnymap` is
+    /// dropped, i.e. when the thread it lives in exits.
+    pub fn get_or_init_with_drop<T: 'static, R>(
+        &self,
+        init: impl FnOnce() -> T,
+        with: impl FnOnce(&T) -> R,
+    ) -> R {
+        let optional_t: &UnsafeCell<Option<T>> = {
+            // SAFETY: see `get_or_init_with`.
+            let map = unsafe { &mut *self.inner.get() };
+            map.entry()
+                .or_insert_with(|| Box::pin(UnsafeCell::new(None)))
+        };
+
+        // SAFETY: see `get_or_init_with`.
+        if unsafe { (*optional_t.get()).is_none() } {
+            let value = init();
+
+            // SAFETY: see `get_or_init_with`.
+            assert!(unsafe { (*optional_t.get()).is_none() }, "reentrant init");
+
+            // SAFETY: see `get_or_init_with`.
+            unsafe { *optional_t.get() = Some(value) };
+
+            // The raw pointer below stays valid for as long as `self` is alive, because entries
+            // are never removed from `inner`. The destructor we're registering here only resets
+            // the entry's value to `None`, and it runs once, from `Drop::drop`, before `inner`
+            // itself is dropped.
+            let raw: *const UnsafeCell<Option<T>> = optional_t;
+            // SAFETY:
+            // `destructors` is only ever touched from this thread (the type is `!Sync`), and
+            // never while a borrow of it from elsewhere in this method is still live.
+            let destructors = unsafe { &mut *self.destructors.get() };
+            destructors.push(Box::new(move || {
+                // SAFETY:
+                // This closure only runs from `Drop::drop`, at which point `self.inner` is
+                // still alive (we're in the middle of tearing it down) and no `with` closure
+                // can be holding a reference into it anymore.
+                unsafe { *(*raw).get() = None };
+            }));
+        }
+
+        // SAFETY: see `get_or_init_with`.
+        let optional_t_ref = unsafe { &*optional_t.get() };
+
+        // SAFETY: see `get_or_init_with`.
+        let t_ref = unsafe { optional_t_ref.as_ref().unwrap_unchecked() };
+
+        with(t_ref)
+    }
+}
+
+impl Drop for ThreadLocalStaticAnymap {
+    fn drop(&mut self) {
+        // SAFETY:
+        // We have `&mut self`, so there can be no other live borrows of `destructors`, nor of
+        // any of the values the registered closures point into.
+        let destructors = unsafe { &mut *self.destructors.get() };
+        for destructor in destructors.drain(..).rev() {
+            destructor();
+        }
+    }
 }
 
 // Compile tests
@@ -99,12 +182,12 @@ const _: () = ();
 /// use generic_singleton::thread_local_static_anymap::ThreadLocalStaticAnymap;
 ///
 /// fn check_t_needs_static<T: Default>(map: &'static ThreadLocalStaticAnymap) {
-///     map.get_or_init_with::<T>(T::default, |_| ());
+///     map.get_or_init_with::<T, _>(T::default, |_| ());
 /// }
 /// ```
 const _: () = ();
 
 #[allow(unused)]
 fn check_t_needs_not_sync_not_send<T: Default + 'static>(map: &'static ThreadLocalStaticAnymap) {
-    map.get_or_init_with::<T>(T::default, |_| ());
+    map.get_or_init_with::<T, _>(T::default, |_| ());
 }
@@ -0,0 +1,47 @@
+//! Backing implementation for the [`tls_get!`](crate::tls_get!), [`tls_set!`](crate::tls_set!),
+//! [`tls_take!`](crate::tls_take!), [`tls_replace!`](crate::tls_replace!) and
+//! [`tls_with_borrow_mut!`](crate::tls_with_borrow_mut!) macros.
+//!
+//! These mirror the convenience methods std added for `LocalKey<Cell<T>>` and
+//! `LocalKey<RefCell<T>>` (RFC 3184), but for a generic-singleton `Cell<T>`/`RefCell<T>` keyed
+//! on `T` instead of one you declare yourself with `thread_local!`. All five share a single
+//! `thread_local!` map so that e.g. a `tls_set::<u32>` and a later `tls_get::<u32>` see the same
+//! cell, regardless of where in the program they're called from.
+
+use std::cell::{Cell, RefCell};
+
+use crate::thread_local_static_anymap::ThreadLocalStaticAnymap;
+
+thread_local! {
+    static CELLS: ThreadLocalStaticAnymap = ThreadLocalStaticAnymap::default();
+}
+
+/// See [`tls_get!`](crate::tls_get!).
+pub fn get<T: Copy + Default + 'static>() -> T {
+    CELLS.with(|map| map.get_or_init_with(|| Cell::new(T::default()), |cell| cell.get()))
+}
+
+/// See [`tls_set!`](crate::tls_set!).
+pub fn set<T: Copy + Default + 'static>(value: T) {
+    CELLS.with(|map| map.get_or_init_with(|| Cell::new(T::default()), |cell| cell.set(value)))
+}
+
+/// See [`tls_take!`](crate::tls_take!).
+pub fn take<T: Copy + Default + 'static>() -> T {
+    CELLS.with(|map| map.get_or_init_with(|| Cell::new(T::default()), |cell| cell.take()))
+}
+
+/// See [`tls_replace!`](crate::tls_replace!).
+pub fn replace<T: Copy + Default + 'static>(value: T) -> T {
+    CELLS.with(|map| map.get_or_init_with(|| Cell::new(T::default()), |cell| cell.replace(value)))
+}
+
+/// See [`tls_with_borrow_mut!`](crate::tls_with_borrow_mut!).
+pub fn with_borrow_mut<T: Default + 'static, R>(with: impl FnOnce(&mut T) -> R) -> R {
+    CELLS.with(|map| {
+        map.get_or_init_with(
+            || RefCell::new(T::default()),
+            |cell| with(&mut cell.borrow_mut()),
+        )
+    })
+}